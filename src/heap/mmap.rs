@@ -0,0 +1,198 @@
+// Address-space-reservation-backed mmap I/O for `Slab`.
+//
+// The trick: reserve a large contiguous `PROT_NONE` region up front, then
+// map the backing file into the front of it with `MAP_FIXED`. Growing the
+// file later just extends the mapping further into the region we already
+// own, so the base address (and every slot pointer derived from it) never
+// moves. Without the up-front reservation, a second `mmap` call for the
+// same file could land anywhere, invalidating any pointer a reader was
+// holding into the first mapping.
+
+use std::{
+    fs::File,
+    os::unix::io::AsRawFd,
+    sync::atomic::{AtomicU64, Ordering::SeqCst},
+    sync::Mutex,
+};
+
+use crate::{Error, Result};
+
+fn page_size() -> u64 {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 }
+}
+
+fn round_up_to_page(len: u64, page_size: u64) -> u64 {
+    (len + page_size - 1) / page_size * page_size
+}
+
+fn round_down_to_page(len: u64, page_size: u64) -> u64 {
+    len - (len % page_size)
+}
+
+/// Bytes of virtual address space reserved per slab. This is never
+/// actually backed by physical memory beyond `mapped_len`, so reserving
+/// generously up front is cheap.
+pub(crate) const RESERVED_LEN: u64 = 1024 * 1024 * 1024;
+
+#[derive(Debug)]
+pub(crate) struct MmapRegion {
+    // raw pointer to the base of the reserved region, stored as a usize;
+    // the unsafe Send + Sync impls at the bottom of this file state why
+    // sharing it across threads is sound
+    base: usize,
+    reserved_len: u64,
+    mapped_len: AtomicU64,
+    // serializes the grow-the-mapping slow path so two threads don't
+    // race to ftruncate + mmap the same file
+    grow_mu: Mutex<()>,
+}
+
+impl Drop for MmapRegion {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base as *mut libc::c_void, self.reserved_len as usize);
+        }
+    }
+}
+
+impl MmapRegion {
+    /// Reserve `RESERVED_LEN` bytes of address space and map in whatever
+    /// of `file` already exists on disk.
+    pub(crate) fn new(file: &File) -> Result<MmapRegion> {
+        let base = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                RESERVED_LEN as usize,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+
+        if base == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        let region = MmapRegion {
+            base: base as usize,
+            reserved_len: RESERVED_LEN,
+            mapped_len: AtomicU64::new(0),
+            grow_mu: Mutex::new(()),
+        };
+
+        let existing_len = file.metadata()?.len();
+        if existing_len > 0 {
+            region.grow_to(file, existing_len)?;
+        }
+
+        Ok(region)
+    }
+
+    /// Ensure at least `needed_len` bytes of `file` are mapped in,
+    /// growing the file and extending the mapping if necessary. Because
+    /// the address space was reserved up front, the base address never
+    /// changes, so any previously-resolved slot pointer stays valid.
+    pub(crate) fn grow_to(&self, file: &File, needed_len: u64) -> Result<()> {
+        if needed_len <= self.mapped_len.load(SeqCst) {
+            return Ok(());
+        }
+
+        let _guard = self.grow_mu.lock().unwrap();
+
+        let mapped_len = self.mapped_len.load(SeqCst);
+        if needed_len <= mapped_len {
+            // another thread beat us to it
+            return Ok(());
+        }
+
+        // The file's logical length must land exactly on `needed_len`,
+        // not some rounded-up value: `Slab::start` derives its
+        // recovered `tip` from this length on the next open, so padding
+        // it out to a page boundary would silently inflate the slot
+        // count on reopen -- especially under `feature = "testing"`,
+        // where a page is many slots.
+        file.set_len(needed_len)?;
+
+        // `mmap(MAP_FIXED)` requires the target address and file offset
+        // to be page-aligned, even though the file's logical length
+        // doesn't have to be -- under `feature = "testing"` slabs are as
+        // small as 32 bytes, well under a page. Re-establish the
+        // mapping from the page boundary at or before the previous one
+        // through a page-aligned length covering `needed_len`; this can
+        // redundantly re-map a page or two already covered by the prior
+        // call, but `MAP_FIXED` over the same fd/offset just replaces
+        // the mapping with an identical one backed by the same page
+        // cache pages, so nothing is lost.
+        let page_size = page_size();
+        let aligned_start = round_down_to_page(mapped_len, page_size);
+        let aligned_end = round_up_to_page(needed_len, page_size);
+
+        assert!(
+            aligned_end <= self.reserved_len,
+            "heap slab grew past its {} byte reserved address space \
+             (requested {} bytes) -- bump RESERVED_LEN",
+            self.reserved_len,
+            aligned_end
+        );
+
+        let grow_by = aligned_end - aligned_start;
+        let addr = (self.base as u64 + aligned_start) as *mut libc::c_void;
+
+        let mapped = unsafe {
+            libc::mmap(
+                addr,
+                grow_by as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_FIXED,
+                file.as_raw_fd(),
+                aligned_start as libc::off_t,
+            )
+        };
+
+        if mapped == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        self.mapped_len.store(needed_len, SeqCst);
+
+        Ok(())
+    }
+
+    /// # Safety
+    /// `offset + len` must be within the currently-mapped length.
+    pub(crate) unsafe fn slice(&self, offset: u64, len: u64) -> &[u8] {
+        let ptr = (self.base as u64 + offset) as *const u8;
+        std::slice::from_raw_parts(ptr, len as usize)
+    }
+
+    /// # Safety
+    /// `offset + len` must be within the currently-mapped length, and the
+    /// caller must not alias this range with another `&mut` or `&` slice.
+    #[allow(clippy::mut_from_ref)]
+    pub(crate) unsafe fn slice_mut(&self, offset: u64, len: u64) -> &mut [u8] {
+        let ptr = (self.base as u64 + offset) as *mut u8;
+        std::slice::from_raw_parts_mut(ptr, len as usize)
+    }
+
+    /// Flush just the given byte range back to the backing file.
+    pub(crate) fn msync_range(&self, offset: u64, len: u64) -> Result<()> {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        let aligned_offset = offset - (offset % page_size);
+        let aligned_len = len + (offset - aligned_offset);
+
+        let addr = (self.base as u64 + aligned_offset) as *mut libc::c_void;
+        let ret = unsafe { libc::msync(addr, aligned_len as usize, libc::MS_SYNC) };
+
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        Ok(())
+    }
+}
+
+// the base pointer is only ever dereferenced through `slice`/`slice_mut`,
+// which require the caller to respect `mapped_len` and avoid aliasing
+unsafe impl Send for MmapRegion {}
+unsafe impl Sync for MmapRegion {}